@@ -0,0 +1,60 @@
+//! Architecture-specific code lives under `arch::<arch_name>`. Everything
+//! outside this module (the scheduler, process management, ...) must go
+//! through the `ArchThread` trait below instead of depending on a
+//! particular architecture's register layout directly.
+
+pub mod x64;
+
+pub use x64::thread::Thread;
+
+use crate::mm::VAddr;
+use crate::result::Result;
+use crate::sync::SpinLockGuard;
+
+use self::x64::{syscall::SyscallFrame, UserVAddr};
+
+/// The architecture-neutral half of a thread's saved CPU state.
+///
+/// Each architecture backend (`arch::x64`, and eventually `arch::riscv64`
+/// or `arch::x86`) provides one implementation of this trait. The
+/// scheduler and process code must only call through `ArchThread` so that
+/// no generic code needs to know about a particular arch's register
+/// names or stack layout.
+pub trait ArchThread: Sized {
+    /// Creates a thread which starts executing in the kernel at `ip` with
+    /// the stack pointer `sp`.
+    fn new_kthread(ip: VAddr, sp: VAddr) -> Self;
+
+    /// Creates a thread which starts executing in userland at `ip` with
+    /// the user stack pointer `sp`. `kernel_sp` is the top of the kernel
+    /// stack used while the thread is in the kernel. `is_compat` marks a
+    /// 32-bit (x86_32 compat) thread, executing a 32-bit ELF binary
+    /// alongside native 64-bit ones.
+    fn new_user_thread(ip: UserVAddr, sp: UserVAddr, kernel_sp: VAddr, is_compat: bool) -> Self;
+
+    /// Creates the per-CPU idle thread.
+    fn new_idle_thread() -> Self;
+
+    /// Clones the current thread's saved state for `fork(2)`, resuming at
+    /// the point captured in `frame`.
+    fn fork(&self, frame: &SyscallFrame) -> Result<Self>;
+
+    /// Rewrites `this`'s saved state so that it resumes in userland at
+    /// `entry_ip` with the user stack pointer `entry_sp` (the entry point
+    /// of a signal handler, with `arg1..arg3` in its argument registers).
+    /// Callers are responsible for having already staged any signal
+    /// frame(s) `entry_sp` depends on (see
+    /// `arch::x64::signal::push_signal_frame`) before calling this.
+    unsafe fn set_signal_entry(
+        this: SpinLockGuard<'_, Self>,
+        entry_ip: u64,
+        entry_sp: u64,
+        arg1: u64,
+        arg2: u64,
+        arg3: u64,
+        is_current_process: bool,
+    );
+
+    /// Switches the CPU from `prev`'s context to `next`'s.
+    fn switch(prev: &mut Self, next: &mut Self);
+}
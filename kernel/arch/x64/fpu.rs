@@ -0,0 +1,125 @@
+//! XSAVE area sizing and lazy FPU/SIMD state switching.
+
+use core::arch::x86_64::{__cpuid, __cpuid_count, _xrstor64, _xsave64};
+
+use super::{cpu_local::cpu_local_head, thread::Thread};
+use crate::mm::page_allocator::num_pages;
+
+/// The size in bytes of the XSAVE area required for the XCR0-enabled
+/// state components on this CPU, as reported by CPUID leaf 0x0D, subleaf
+/// 0 (in EBX; ECX is the max size for all components the CPU *supports*,
+/// not just the ones enabled in XCR0). Falls back to the legacy FXSAVE
+/// size if CPUID somehow reports a bogus value.
+pub fn xsave_area_size() -> usize {
+    let leaf = unsafe { __cpuid_count(0x0D, 0) };
+    clamp_to_legacy_fxsave_size(leaf.ebx as usize)
+}
+
+/// The fallback arithmetic `xsave_area_size` applies to CPUID's reported
+/// size, split out so it can be tested without depending on CPUID.
+fn clamp_to_legacy_fxsave_size(size: usize) -> usize {
+    const LEGACY_FXSAVE_SIZE: usize = 512;
+
+    if size < LEGACY_FXSAVE_SIZE {
+        LEGACY_FXSAVE_SIZE
+    } else {
+        size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clamps_bogus_or_undersized_cpuid_reports() {
+        assert_eq!(clamp_to_legacy_fxsave_size(0), 512);
+        assert_eq!(clamp_to_legacy_fxsave_size(511), 512);
+    }
+
+    #[test]
+    fn passes_through_sizes_at_or_above_legacy_fxsave_size() {
+        assert_eq!(clamp_to_legacy_fxsave_size(512), 512);
+        assert_eq!(clamp_to_legacy_fxsave_size(2560), 2560);
+    }
+}
+
+/// The number of pages to allocate for a thread's `xsave_area`.
+pub fn xsave_area_num_pages() -> usize {
+    num_pages(xsave_area_size())
+}
+
+/// CPUID leaf 1, ECX bit 26: the CPU supports `XSAVE`/`XRSTOR` at all.
+/// Every CPU Kerla actually boots on has it, but keep the check so a
+/// future target that lacks it (or some other CPU that can't be trusted
+/// to raise `#NM` reliably) falls back to eager save/restore on every
+/// switch instead of silently losing FPU state.
+fn lazy_switching_supported() -> bool {
+    unsafe { __cpuid(1).ecx & (1 << 26) != 0 }
+}
+
+/// Marks the current thread's FPU/SIMD state as not-present in the
+/// registers. The next FP/SIMD instruction it executes traps into
+/// `#NM`, where we lazily restore it.
+pub unsafe fn set_fpu_disabled() {
+    x86::controlregs::cr0_write(x86::controlregs::cr0() | x86::controlregs::Cr0::CR0_TASK_SWITCHED);
+}
+
+/// Switches the FPU/SIMD state from `prev` to `next`. Prefers the lazy
+/// path (just mark the state not-present and let `#NM` do the work only
+/// if `next` actually touches SIMD); falls back to an eager save/restore,
+/// right here, for CPUs `lazy_switching_supported` distrusts.
+pub unsafe fn switch_fpu_state(prev: &Thread, next: &Thread) {
+    if lazy_switching_supported() {
+        set_fpu_disabled();
+        return;
+    }
+
+    let xsave_mask = x86::controlregs::xcr0().bits();
+    if let Some(xsave_area) = prev.xsave_area.as_ref() {
+        _xsave64(xsave_area.as_mut_ptr(), xsave_mask);
+    }
+    if let Some(xsave_area) = next.xsave_area.as_ref() {
+        _xrstor64(xsave_area.as_mut_ptr(), xsave_mask);
+    }
+}
+
+/// `#NM` (device-not-available) handler: clears CR0.TS, saves the
+/// previous FPU owner's XSAVE area (if any), and restores `current`'s.
+///
+/// Threads that never execute a FP/SIMD instruction never reach this
+/// handler and thus never pay for a save/restore.
+pub unsafe fn handle_device_not_available(current: &mut Thread) {
+    x86::controlregs::cr0_write(x86::controlregs::cr0() & !x86::controlregs::Cr0::CR0_TASK_SWITCHED);
+
+    let head = cpu_local_head();
+    let xsave_mask = x86::controlregs::xcr0().bits();
+
+    if let Some(owner) = head.fpu_owner {
+        if owner == current as *mut Thread {
+            // Already the owner; nothing to do (spurious #NM).
+            return;
+        }
+        if let Some(xsave_area) = (*owner).xsave_area.as_ref() {
+            _xsave64(xsave_area.as_mut_ptr(), xsave_mask);
+        }
+    }
+
+    if let Some(xsave_area) = current.xsave_area.as_ref() {
+        _xrstor64(xsave_area.as_mut_ptr(), xsave_mask);
+    }
+
+    head.fpu_owner = Some(current as *mut Thread);
+}
+
+/// Must be called when `thread` is torn down (process exit/reap) or
+/// migrated off the CPU that last owned the FPU for it, before its
+/// `xsave_area` is freed or becomes invalid. Otherwise a later `#NM` on
+/// this CPU would `_xsave64` the new owner's state into `thread`'s
+/// now-dangling `xsave_area` via the stale `fpu_owner` pointer.
+pub unsafe fn forget_fpu_owner(thread: *mut Thread) {
+    let head = cpu_local_head();
+    if head.fpu_owner == Some(thread) {
+        head.fpu_owner = None;
+    }
+}
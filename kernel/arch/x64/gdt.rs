@@ -0,0 +1,166 @@
+//! The Global Descriptor Table.
+
+use super::tss::TSS;
+
+const FLAGS_PRESENT: u64 = 1 << 47;
+const FLAGS_USER: u64 = 1 << 44; // Code/data (as opposed to a system descriptor).
+const FLAGS_EXECUTABLE: u64 = 1 << 43;
+const FLAGS_RW: u64 = 1 << 41;
+const FLAGS_DPL3: u64 = 3 << 45;
+const FLAGS_LONG_MODE: u64 = 1 << 53; // 64-bit code segment.
+const FLAGS_DB: u64 = 1 << 54; // 32-bit code/data segment (operand size).
+const FLAGS_GRANULARITY: u64 = 1 << 55;
+const FLAGS_LIMIT_LOW: u64 = 0xffff;
+const FLAGS_LIMIT_HIGH: u64 = 0xf << 48;
+
+const fn data_desc(dpl3: bool) -> u64 {
+    let mut desc = FLAGS_PRESENT | FLAGS_USER | FLAGS_RW | FLAGS_DB | FLAGS_GRANULARITY;
+    desc |= FLAGS_LIMIT_LOW | FLAGS_LIMIT_HIGH;
+    if dpl3 {
+        desc |= FLAGS_DPL3;
+    }
+    desc
+}
+
+const fn code64_desc(dpl3: bool) -> u64 {
+    let mut desc = FLAGS_PRESENT | FLAGS_USER | FLAGS_EXECUTABLE | FLAGS_RW | FLAGS_LONG_MODE;
+    if dpl3 {
+        desc |= FLAGS_DPL3;
+    }
+    desc
+}
+
+const fn code32_desc(dpl3: bool) -> u64 {
+    let mut desc = FLAGS_PRESENT
+        | FLAGS_USER
+        | FLAGS_EXECUTABLE
+        | FLAGS_RW
+        | FLAGS_DB
+        | FLAGS_GRANULARITY
+        | FLAGS_LIMIT_LOW
+        | FLAGS_LIMIT_HIGH;
+    if dpl3 {
+        desc |= FLAGS_DPL3;
+    }
+    desc
+}
+
+// GDT entry indices. The TSS descriptor is 16 bytes (two slots) in long
+// mode.
+const NULL_INDEX: u16 = 0;
+const KERNEL_CS_INDEX: u16 = 1;
+const KERNEL_DS_INDEX: u16 = 2;
+const USER_DS_INDEX: u16 = 3;
+const USER_CS64_INDEX: u16 = 4;
+const USER_CS32_INDEX: u16 = 5;
+const USER_DS32_INDEX: u16 = 6;
+const TSS_INDEX: u16 = 7;
+
+pub const KERNEL_CS: u16 = KERNEL_CS_INDEX << 3;
+pub const KERNEL_DS: u16 = KERNEL_DS_INDEX << 3;
+pub const USER_DS: u16 = USER_DS_INDEX << 3;
+pub const USER_CS64: u16 = USER_CS64_INDEX << 3;
+
+/// The 32-bit (x86_32 compat) code segment: same privilege level as
+/// `USER_CS64`, but without `FLAGS_LONG_MODE` and with the 32-bit
+/// operand-size/granularity bits set, so a compat thread's `IRET` lands
+/// back in 32-bit mode instead of long mode.
+pub const USER_CS32: u16 = USER_CS32_INDEX << 3;
+
+/// The 32-bit compat data segment. x86_64 data segments are otherwise
+/// mostly ignored by the CPU, but compat mode still uses the descriptor's
+/// limit/flags, so it gets its own entry rather than reusing `USER_DS`.
+pub const USER_DS32: u16 = USER_DS32_INDEX << 3;
+
+pub const TSS_SEL: u16 = TSS_INDEX << 3;
+
+/// Requested privilege level (ring 3) ORed into a segment selector.
+pub const USER_RPL: u16 = 3;
+
+#[repr(C, align(16))]
+struct Gdt([u64; 9]);
+
+static mut GDT: Gdt = Gdt([
+    0,                    // Null descriptor.
+    code64_desc(false),   // Kernel CS.
+    data_desc(false),     // Kernel DS.
+    data_desc(true),      // User DS (64-bit).
+    code64_desc(true),    // User CS (64-bit).
+    code32_desc(true),    // User CS (32-bit compat).
+    data_desc(true),      // User DS (32-bit compat).
+    0,                    // TSS descriptor, low qword: filled in by init().
+    0,                    // TSS descriptor, high qword (base bits 32-63).
+]);
+
+/// Builds the TSS descriptor (a 16-byte system descriptor in long mode)
+/// pointing at `TSS` and loads the GDT and task register.
+pub fn init() {
+    unsafe {
+        let base = &TSS as *const _ as u64;
+        let limit = core::mem::size_of_val(&TSS) as u64 - 1;
+
+        let low = FLAGS_PRESENT
+            | (0b1001 << 40) // 64-bit TSS (available) system descriptor type.
+            | (limit & 0xffff)
+            | ((limit & 0xf_0000) << 32)
+            | ((base & 0xff_ffff) << 16)
+            | (((base >> 24) & 0xff) << 56);
+        let high = base >> 32;
+
+        GDT.0[TSS_INDEX as usize] = low;
+        GDT.0[TSS_INDEX as usize + 1] = high;
+
+        x86::dtables::lgdt(&x86::dtables::DescriptorTablePointer::new_from_slice(&GDT.0));
+        x86::task::load_tr(x86::Ring::Ring0, x86::segmentation::SegmentSelector::new(
+            TSS_INDEX,
+            x86::Ring::Ring0,
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dpl(desc: u64) -> u64 {
+        (desc >> 45) & 0b11
+    }
+
+    #[test]
+    fn kernel_descriptors_are_dpl0() {
+        assert_eq!(dpl(code64_desc(false)), 0);
+        assert_eq!(dpl(data_desc(false)), 0);
+    }
+
+    #[test]
+    fn user_descriptors_are_dpl3() {
+        assert_eq!(dpl(code64_desc(true)), 3);
+        assert_eq!(dpl(data_desc(true)), 3);
+        assert_eq!(dpl(code32_desc(true)), 3);
+    }
+
+    #[test]
+    fn code64_desc_is_present_executable_and_long_mode() {
+        let desc = code64_desc(true);
+        assert_ne!(desc & FLAGS_PRESENT, 0);
+        assert_ne!(desc & FLAGS_EXECUTABLE, 0);
+        assert_ne!(desc & FLAGS_LONG_MODE, 0);
+        assert_eq!(desc & FLAGS_DB, 0);
+    }
+
+    #[test]
+    fn code32_desc_is_32_bit_not_long_mode() {
+        let desc = code32_desc(true);
+        assert_ne!(desc & FLAGS_PRESENT, 0);
+        assert_ne!(desc & FLAGS_EXECUTABLE, 0);
+        assert_ne!(desc & FLAGS_DB, 0);
+        assert_eq!(desc & FLAGS_LONG_MODE, 0);
+    }
+
+    #[test]
+    fn data_desc_is_present_and_not_executable() {
+        let desc = data_desc(true);
+        assert_ne!(desc & FLAGS_PRESENT, 0);
+        assert_eq!(desc & FLAGS_EXECUTABLE, 0);
+    }
+}
@@ -0,0 +1,28 @@
+//! Timer IRQ and interrupt-return hooks.
+//!
+//! Both functions here are the link-time contract with the IDT dispatch:
+//! the timer vector's handler must call `handle_timer_interrupt` after
+//! sending EOI, and the common interrupt/exception epilogue (where every
+//! vector converges before IRET) must call `interrupt_return` with
+//! whether the interrupted context was in the kernel. `#[no_mangle]` so
+//! those asm/low-level dispatch stubs can call them by symbol name.
+
+use super::preempt;
+
+/// Timer IRQ handler. Called by the IDT dispatch on every tick; just
+/// requests a reschedule at the next `preempt_if_needed` opportunity
+/// rather than calling into the scheduler directly, since we may be deep
+/// inside a `preempt_disable` section right now.
+#[no_mangle]
+pub extern "C" fn handle_timer_interrupt() {
+    preempt::set_need_resched();
+}
+
+/// Called on the way out of every interrupt/exception handler, after
+/// registers are restored but before IRET. `was_in_kernel` is whether the
+/// interrupted context was running in the kernel (userland already goes
+/// through the scheduler on its own return path).
+#[no_mangle]
+pub extern "C" fn interrupt_return(was_in_kernel: bool) {
+    preempt::preempt_if_needed(was_in_kernel);
+}
@@ -0,0 +1,22 @@
+//! The x86_64 architecture backend: GDT/TSS setup, context switching, the
+//! FPU and signal-frame machinery, and compat (x86_32) syscall dispatch.
+
+mod address;
+mod cpu_local;
+pub mod fpu;
+pub mod gdt;
+pub mod interrupt;
+pub mod preempt;
+pub mod signal;
+pub mod syscall;
+pub mod syscall32;
+pub mod thread;
+mod tss;
+
+pub use address::{UserVAddr, VAddr};
+pub use crate::sync::SpinLockGuard;
+
+/// The size of each of a thread's kernel-mode stacks (`interrupt_stack`
+/// and `syscall_stack`), matching the single page `alloc_pages(1, ...)`
+/// allocates for each in `Thread::new_user_thread`/`fork`.
+pub const KERNEL_STACK_SIZE: usize = 4096;
@@ -0,0 +1,67 @@
+//! Per-CPU preempt counter: the kernel may only be rescheduled out from
+//! under an interrupt when `preempt_count == 0`.
+
+use super::cpu_local::cpu_local_head;
+
+/// Increments the current CPU's preempt counter, marking a critical
+/// section during which the running thread must not be rescheduled.
+pub fn preempt_disable() {
+    cpu_local_head().preempt_count += 1;
+}
+
+/// Decrements the current CPU's preempt counter.
+pub fn preempt_enable() {
+    let head = cpu_local_head();
+    debug_assert!(
+        head.preempt_count > 0,
+        "preempt_enable() without a matching preempt_disable()"
+    );
+    head.preempt_count -= 1;
+}
+
+/// Whether the running thread may currently be preempted, i.e. no
+/// `preempt_disable` guard is held.
+pub fn preemptible() -> bool {
+    cpu_local_head().preempt_count == 0
+}
+
+/// Balances the `preempt_disable()` that `Thread::switch` takes before
+/// pivoting onto a thread's stack, for a thread that is running for the
+/// very first time.
+///
+/// A thread that's been scheduled before resumes *inside* `switch()`'s
+/// call to `do_switch_thread`, so the `preempt_enable()` at `switch()`'s
+/// own tail undoes the disable. A freshly created thread instead starts
+/// at its entry trampoline (`kthread_entry`, `userland_entry`,
+/// `forked_child_entry`, `signal_handler_entry`, in `entry.S`) and never
+/// runs that tail, so each trampoline calls this, exactly once, as the
+/// first thing it does, or the disable leaks forever.
+///
+/// `#[no_mangle]`/`extern "C"` because `entry.S` calls this directly by
+/// symbol name.
+#[no_mangle]
+pub extern "C" fn schedule_tail() {
+    preempt_enable();
+}
+
+/// Requests that the scheduler run on the next opportunity, i.e. the
+/// next time `preempt_if_needed` sees `preempt_count == 0`. Called by the
+/// timer interrupt handler.
+pub fn set_need_resched() {
+    cpu_local_head().need_resched = true;
+}
+
+/// Called on the return path from every interrupt. `was_in_kernel`
+/// indicates whether the interrupted context was running in the kernel
+/// (as opposed to userland, which is already rescheduled on its way back
+/// in through the usual syscall/interrupt return path). If preemption is
+/// enabled and a reschedule was requested, runs the scheduler right here
+/// so `switch_thread` can run mid-kernel-execution instead of waiting for
+/// a voluntary yield point.
+pub fn preempt_if_needed(was_in_kernel: bool) {
+    let head = cpu_local_head();
+    if was_in_kernel && head.need_resched && preemptible() {
+        head.need_resched = false;
+        crate::process::scheduler::reschedule();
+    }
+}
@@ -0,0 +1,460 @@
+//! Signal frames and `sigreturn`.
+
+use super::{syscall::SyscallFrame, syscall32::CompatSyscallFrame, UserVAddr};
+
+// Below the red zone the ABI guarantees leaf functions won't clobber.
+const RED_ZONE_LEN: u64 = 128;
+
+extern "C" {
+    /// The trampoline left as the return address on the user stack. It
+    /// does nothing but `mov rax, SYS_RT_SIGRETURN; syscall`, trapping
+    /// back into the kernel so `sys_rt_sigreturn` can restore
+    /// `SignalFrame`.
+    fn signal_trampoline();
+
+    /// The compat counterpart of `signal_trampoline`, reachable from a
+    /// 32-bit address space: `mov eax, SYS_RT_SIGRETURN; int 0x80`.
+    /// `signal_trampoline` itself can't be reused here — it's native
+    /// 64-bit code, and truncating its address to 32 bits wouldn't even
+    /// point at it anymore, let alone at something executable from a
+    /// compat thread's ring-3 CS.
+    fn compat_signal_trampoline();
+}
+
+/// The register state to resume once a signal frame's `sigreturn` runs:
+/// either the genuinely-interrupted context, or (when chaining multiple
+/// pending signals) the entry state of the next handler to run.
+///
+/// Does not track R11: at a `syscall` entry it's guaranteed equal to
+/// RFLAGS and carries no extra information, so `SyscallFrame` (what
+/// `from_interrupted` reads) doesn't save it either. This is only sound
+/// because `kernel::signal::deliver_pending_signals` takes a syscall
+/// entry frame (`InterruptedFrame`) and must only ever be called from a genuine syscall
+/// entry — never from an asynchronous IRQ/timer frame, where R11 is a
+/// live register, not a copy of RFLAGS. A future interrupt-time delivery
+/// path needs its own `Resume`-like type with a real R11 slot; it must
+/// not reuse this one.
+///
+/// `ecx` is the one exception: a `syscall` entry aliases it with RIP the
+/// same way R11 aliases RFLAGS, so the native path leaves it zeroed
+/// here too. But `int 0x80` is a plain interrupt-gate entry, so
+/// `CompatSyscallFrame` never aliased it with anything — `ecx` is a
+/// real, live general-purpose register (and the second syscall
+/// argument) at the moment of interruption, so `from_compat_interrupted`
+/// must capture it and `Signal32Frame` must restore it, or every signal
+/// delivered to a compat thread permanently clobbers its ECX.
+#[derive(Clone, Copy)]
+pub struct Resume {
+    pub rip: u64,
+    pub rsp: u64,
+    pub rflags: u64,
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+}
+
+impl Resume {
+    /// The context a signal actually interrupted.
+    pub fn from_interrupted(frame: &SyscallFrame) -> Resume {
+        Resume {
+            rip: frame.rip,
+            rsp: frame.rsp,
+            rflags: frame.rflags,
+            rax: frame.rax,
+            rbx: frame.rbx,
+            rcx: 0, // Aliased with RIP at a `syscall` entry; see this struct's doc comment.
+            rdx: frame.rdx,
+            rsi: frame.rsi,
+            rdi: frame.rdi,
+            rbp: frame.rbp,
+            r8: frame.r8,
+            r9: frame.r9,
+            r10: frame.r10,
+            r12: frame.r12,
+            r13: frame.r13,
+            r14: frame.r14,
+            r15: frame.r15,
+        }
+    }
+
+    /// The context a signal actually interrupted, for a compat thread
+    /// trapped through `int 0x80` instead of `syscall`. R8-R15 aren't
+    /// part of the 32-bit ABI, so they're left zeroed the same way
+    /// `into_handler` zeroes them for a fresh handler call. Unlike
+    /// `from_interrupted`, `ecx` is a real live register here (see this
+    /// struct's doc comment) and must be captured.
+    pub fn from_compat_interrupted(frame: &CompatSyscallFrame) -> Resume {
+        Resume {
+            rip: frame.rip,
+            rsp: frame.rsp,
+            rflags: frame.rflags,
+            rax: frame.eax as u64,
+            rbx: frame.ebx as u64,
+            rcx: frame.ecx as u64,
+            rdx: frame.edx as u64,
+            rsi: frame.esi as u64,
+            rdi: frame.edi as u64,
+            rbp: frame.ebp as u64,
+            r8: 0,
+            r9: 0,
+            r10: 0,
+            r12: 0,
+            r13: 0,
+            r14: 0,
+            r15: 0,
+        }
+    }
+
+    /// The entry state of a handler about to run, with `arg1..arg3` in
+    /// the same registers a fresh call to it would use.
+    pub fn into_handler(handler_ip: u64, handler_sp: u64, arg1: u64, arg2: u64, arg3: u64) -> Resume {
+        Resume {
+            rip: handler_ip,
+            rsp: handler_sp,
+            rflags: 0x202, // Interrupts enabled.
+            rax: 0,
+            rbx: 0,
+            rcx: 0,
+            rdx: arg3,
+            rsi: arg2,
+            rdi: arg1,
+            rbp: 0,
+            r8: 0,
+            r9: 0,
+            r10: 0,
+            r12: 0,
+            r13: 0,
+            r14: 0,
+            r15: 0,
+        }
+    }
+}
+
+/// Everything needed to resume `resume` once this frame's `sigreturn`
+/// runs. Pushed onto the user stack below the red zone, with the
+/// trampoline address just below it; the handler's arguments are passed
+/// in registers, not on the stack.
+#[repr(C, packed)]
+pub struct SignalFrame {
+    pub sigmask: u64,
+    pub rax: u64,
+    pub rbx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+}
+
+impl SignalFrame {
+    pub fn build(resume: &Resume, sigmask: u64) -> SignalFrame {
+        SignalFrame {
+            sigmask,
+            rax: resume.rax,
+            rbx: resume.rbx,
+            rdx: resume.rdx,
+            rsi: resume.rsi,
+            rdi: resume.rdi,
+            rbp: resume.rbp,
+            r8: resume.r8,
+            r9: resume.r9,
+            r10: resume.r10,
+            r12: resume.r12,
+            r13: resume.r13,
+            r14: resume.r14,
+            r15: resume.r15,
+            rip: resume.rip,
+            rflags: resume.rflags,
+            rsp: resume.rsp,
+        }
+    }
+
+    /// Writes the trampoline address just below this frame on the stack
+    /// pointed to by `rsp`, returning the new (lower) stack pointer the
+    /// handler should be entered with.
+    pub unsafe fn push(self, mut rsp: *mut u64) -> *mut u64 {
+        rsp = rsp.sub(core::mem::size_of::<SignalFrame>() / 8);
+        (rsp as *mut SignalFrame).write_unaligned(self);
+        rsp = rsp.sub(1);
+        rsp.write(signal_trampoline as *const u8 as u64);
+        rsp
+    }
+
+    /// Restores `frame` (the syscall frame `sys_rt_sigreturn` was
+    /// entered through) from the frame previously pushed by `push`,
+    /// reading it off `rsp` (the user stack pointer at the time
+    /// `sys_rt_sigreturn` was called) and returning the signal mask that
+    /// should be restored in the process's blocked-signal set.
+    pub unsafe fn pop(frame: &mut SyscallFrame, rsp: UserVAddr) -> u64 {
+        let saved = (rsp.value() as *const SignalFrame).read_unaligned();
+
+        frame.rax = saved.rax;
+        frame.rbx = saved.rbx;
+        frame.rdx = saved.rdx;
+        frame.rsi = saved.rsi;
+        frame.rdi = saved.rdi;
+        frame.rbp = saved.rbp;
+        frame.r8 = saved.r8;
+        frame.r9 = saved.r9;
+        frame.r10 = saved.r10;
+        frame.r12 = saved.r12;
+        frame.r13 = saved.r13;
+        frame.r14 = saved.r14;
+        frame.r15 = saved.r15;
+        frame.rip = saved.rip;
+        frame.rflags = saved.rflags;
+        frame.rsp = saved.rsp;
+
+        saved.sigmask
+    }
+}
+
+/// The 32-bit (x86_32 compat) equivalent of `SignalFrame`. Unlike
+/// `SignalFrame`, this carries `ecx`: `int 0x80` never aliases it with
+/// anything (see `Resume`'s doc comment), so it's a real register that
+/// must round-trip through `sigreturn` like any other.
+#[repr(C, packed)]
+pub struct Signal32Frame {
+    pub sigmask: u32,
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+    pub esi: u32,
+    pub edi: u32,
+    pub ebp: u32,
+    pub eip: u32,
+    pub eflags: u32,
+    pub esp: u32,
+}
+
+impl Signal32Frame {
+    pub fn build(resume: &Resume, sigmask: u64) -> Signal32Frame {
+        Signal32Frame {
+            sigmask: sigmask as u32,
+            eax: resume.rax as u32,
+            ebx: resume.rbx as u32,
+            ecx: resume.rcx as u32,
+            edx: resume.rdx as u32,
+            esi: resume.rsi as u32,
+            edi: resume.rdi as u32,
+            ebp: resume.rbp as u32,
+            eip: resume.rip as u32,
+            eflags: resume.rflags as u32,
+            esp: resume.rsp as u32,
+        }
+    }
+
+    /// Same idea as `SignalFrame::push`, but the trampoline address and
+    /// the frame itself are only 32 bits wide, matching what a compat
+    /// binary's stack holds. Uses `compat_signal_trampoline`, not
+    /// `signal_trampoline`: the two are different code, not just
+    /// different-width pointers to the same thing.
+    pub unsafe fn push(self, rsp: *mut u64) -> *mut u64 {
+        let rsp32 = rsp as *mut u32;
+        let rsp32 = rsp32.sub(core::mem::size_of::<Signal32Frame>() / 4);
+        (rsp32 as *mut Signal32Frame).write_unaligned(self);
+        let rsp32 = rsp32.sub(1);
+        rsp32.write(compat_signal_trampoline as *const u8 as u32);
+        rsp32 as *mut u64
+    }
+
+    /// Same idea as `SignalFrame::pop`, but also returns the restored
+    /// `ecx`: `SyscallFrame` has no slot for it (it's never aliased with
+    /// RIP the way the native path's RCX is, so there's nothing to
+    /// overload), so the caller is responsible for writing it back into
+    /// wherever the thread's actual resume context lives — the
+    /// `CompatSyscallFrame` its `int 0x80` trapped in through.
+    pub unsafe fn pop(frame: &mut SyscallFrame, rsp: UserVAddr) -> (u64, u32) {
+        let saved = (rsp.value() as *const Signal32Frame).read_unaligned();
+
+        frame.rax = saved.eax as u64;
+        frame.rbx = saved.ebx as u64;
+        frame.rdx = saved.edx as u64;
+        frame.rsi = saved.esi as u64;
+        frame.rdi = saved.edi as u64;
+        frame.rbp = saved.ebp as u64;
+        frame.rip = saved.eip as u64;
+        frame.rflags = saved.eflags as u64;
+        frame.rsp = saved.esp as u64;
+
+        (saved.sigmask as u64, saved.ecx)
+    }
+}
+
+/// Builds a signal frame preserving `resume` (under `sigmask`) below the
+/// red zone of the stack whose current top is `stack_top`, choosing the
+/// 32-bit or 64-bit frame layout per `is_compat`. Returns the new stack
+/// pointer, i.e. where the thing resuming on top of this frame (a
+/// handler, or nothing if this is the outermost call) should be entered
+/// with.
+///
+/// This only writes to the user stack; it doesn't touch the thread's
+/// saved kernel-side registers or activate anything, so callers can
+/// stage as many of these as there are pending signals before handing
+/// the final (outermost) entry point to `ArchThread::set_signal_entry`.
+pub unsafe fn push_signal_frame(resume: &Resume, sigmask: u64, is_compat: bool, stack_top: u64) -> u64 {
+    let rsp = (stack_top - RED_ZONE_LEN) as *mut u64;
+    let new_rsp = if is_compat {
+        Signal32Frame::build(resume, sigmask).push(rsp)
+    } else {
+        SignalFrame::build(resume, sigmask).push(rsp)
+    };
+    new_rsp as u64
+}
+
+/// The new stack pointer `push_signal_frame` would return for the same
+/// arguments, computed as pure arithmetic with no memory access. Lets a
+/// caller that can't write the frame yet (see `PendingSignalFrame`)
+/// still thread the right `entry_sp`/`stack_top` through the rest of the
+/// chain.
+fn signal_frame_new_rsp(is_compat: bool, stack_top: u64) -> u64 {
+    let base = stack_top - RED_ZONE_LEN;
+    if is_compat {
+        let bytes = (core::mem::size_of::<Signal32Frame>() as u64 / 4 + 1) * 4;
+        base - bytes
+    } else {
+        let bytes = (core::mem::size_of::<SignalFrame>() as u64 / 8 + 1) * 8;
+        base - bytes
+    }
+}
+
+/// A signal frame that has been fully decided (the `Resume` it restores,
+/// its `sigmask` and where it goes) but not yet written to the user
+/// stack.
+///
+/// `push_signal_frame` requires the target's page tables to be the ones
+/// currently active, which only holds for the process delivering to
+/// itself. When delivering to a different process, the frame's final
+/// address is still computable (`signal_frame_new_rsp` is pure
+/// arithmetic), but the write itself must wait until that process's own
+/// address space is active again — i.e. its own return-to-userland path
+/// must drain `Thread::pending_signal_frames` and call `commit` on each
+/// before reaching userland.
+pub struct PendingSignalFrame {
+    resume: Resume,
+    sigmask: u64,
+    is_compat: bool,
+    stack_top: u64,
+}
+
+impl PendingSignalFrame {
+    pub fn new(resume: Resume, sigmask: u64, is_compat: bool, stack_top: u64) -> PendingSignalFrame {
+        PendingSignalFrame {
+            resume,
+            sigmask,
+            is_compat,
+            stack_top,
+        }
+    }
+
+    /// The stack pointer this frame will hand to whatever resumes on top
+    /// of it, computed without touching memory.
+    pub fn new_rsp(&self) -> u64 {
+        signal_frame_new_rsp(self.is_compat, self.stack_top)
+    }
+
+    /// Actually writes the frame to the user stack. Only safe once this
+    /// thread's address space is the one active on the current CPU.
+    pub unsafe fn commit(&self) {
+        push_signal_frame(&self.resume, self.sigmask, self.is_compat, self.stack_top);
+    }
+}
+
+/// `sys_rt_sigreturn(2)`: pops the signal frame the kernel pushed before
+/// entering the handler off the user stack (pointed to by `frame.rsp`,
+/// which is where the trampoline's `syscall` trapped in from) and
+/// restores `frame` from it, so the syscall return path resumes the
+/// thread exactly as it was before the signal arrived.
+///
+/// Returns the signal mask to restore as the process's blocked-signal
+/// set, plus (for a compat thread) the `ecx` `Signal32Frame::pop`
+/// restored. `SyscallFrame` has no slot for it, so unlike every other
+/// register this one doesn't come back already written into `frame` —
+/// the caller must write it into the real `CompatSyscallFrame` that
+/// thread resumes through instead.
+pub unsafe fn sys_rt_sigreturn(frame: &mut SyscallFrame, is_compat: bool) -> (u64, Option<u32>) {
+    let rsp = UserVAddr::new_unchecked(frame.rsp);
+    if is_compat {
+        let (sigmask, ecx) = Signal32Frame::pop(frame, rsp);
+        (sigmask, Some(ecx))
+    } else {
+        (SignalFrame::pop(frame, rsp), None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // `{Signal,Signal32}Frame::push` only ever take these symbols'
+    // addresses (to leave them as the handler's return address); the
+    // real ones aren't linked into a host test binary. Stub them out so
+    // `push_signal_frame` can run for real below.
+    #[no_mangle]
+    extern "C" fn signal_trampoline() {}
+    #[no_mangle]
+    extern "C" fn compat_signal_trampoline() {}
+
+    fn dummy_resume() -> Resume {
+        Resume {
+            rip: 0x4000_1000,
+            rsp: 0x4000_2000,
+            rflags: 0x202,
+            rax: 1,
+            rbx: 2,
+            rcx: 14,
+            rdx: 3,
+            rsi: 4,
+            rdi: 5,
+            rbp: 6,
+            r8: 7,
+            r9: 8,
+            r10: 9,
+            r12: 10,
+            r13: 11,
+            r14: 12,
+            r15: 13,
+        }
+    }
+
+    #[test]
+    fn new_rsp_matches_what_signal_frame_push_writes() {
+        let mut stack = [0u64; 64];
+        let stack_top = unsafe { stack.as_mut_ptr().add(stack.len()) } as u64;
+
+        let written_rsp = unsafe { push_signal_frame(&dummy_resume(), 0xabcd, false, stack_top) };
+
+        assert_eq!(signal_frame_new_rsp(false, stack_top), written_rsp);
+    }
+
+    #[test]
+    fn new_rsp_matches_what_signal32_frame_push_writes() {
+        let mut stack = [0u64; 64];
+        let stack_top = unsafe { stack.as_mut_ptr().add(stack.len()) } as u64;
+
+        let written_rsp = unsafe { push_signal_frame(&dummy_resume(), 0xabcd, true, stack_top) };
+
+        assert_eq!(signal_frame_new_rsp(true, stack_top), written_rsp);
+    }
+}
@@ -0,0 +1,70 @@
+//! Compat (x86_32) syscall dispatch.
+//!
+//! A 32-bit binary enters the kernel through `int 0x80` and passes its
+//! syscall number and arguments in the 32-bit ABI register layout:
+//! syscall number in `eax`, arguments in `ebx`, `ecx`, `edx`, `esi`,
+//! `edi`, `ebp` (instead of `rax`, `rdi`, `rsi`, `rdx`, `r10`, `r8`,
+//! `r9`). `dispatch_compat_syscall` reads the compat layout and forwards
+//! it to the same syscall table the native 64-bit path uses.
+//!
+//! This can't reuse `SyscallFrame`: that struct models the `syscall`
+//! fast-path entry, where the CPU itself clobbers RCX/R11 with
+//! RIP/RFLAGS, so it has no slot to hold a real second argument. `int
+//! 0x80` is a plain interrupt-gate entry instead, so none of its GPRs
+//! are clobbered and `CompatSyscallFrame` saves all of them as-is. Being
+//! a genuine ring3->ring0 interrupt-gate entry, the CPU also pushes a
+//! real 5-word IRET frame below the GPRs — SS, RSP, RFLAGS, CS, RIP, in
+//! that push order (so RIP ends up adjacent to the GPRs, SS furthest
+//! away) — which `arch::x64::signal`'s `Resume::from_compat_interrupted`
+//! needs to resume a compat thread signaled while blocked in a syscall.
+
+use crate::result::Result;
+use crate::syscalls::dispatch_syscall;
+
+/// The full register state saved on entry through `int 0x80`, in the
+/// 32-bit ABI layout. Unlike `SyscallFrame`, every field here is a real
+/// saved register (an interrupt gate doesn't clobber any of them), so
+/// `ecx` genuinely holds the second syscall argument.
+#[repr(C, packed)]
+pub struct CompatSyscallFrame {
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+    pub esi: u32,
+    pub edi: u32,
+    pub ebp: u32,
+    /// The interrupt-gate IRET frame the CPU pushed below the GPRs
+    /// above, in the order it actually lands in memory (`rip` adjacent
+    /// to the GPRs, `ss` furthest away) — not just `rip`/`rflags`/`rsp`:
+    /// a ring-crossing `int 0x80` always pushes the full 5-word frame,
+    /// `cs`/`ss` included, and skipping them here would silently read
+    /// `cs`'s bytes as `rflags` and `rflags`'s bytes as `rsp`. Stored as
+    /// `u64`s (not `u32`s) because, even interrupting a 32-bit compat
+    /// thread, the CPU is still running in long mode and always pushes
+    /// 8-byte slots here — the same reason `new_user_thread` builds its
+    /// IRET frame out of `u64` pushes for compat threads too.
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+/// Reads a compat (`int 0x80`) syscall out of `frame` and runs it
+/// through the regular syscall table, so 32-bit binaries share the same
+/// syscall implementations as native 64-bit ones.
+pub fn dispatch_compat_syscall(frame: &CompatSyscallFrame) -> Result<isize> {
+    let syscall_no = frame.eax as i32;
+    // Sign-extend each arg like real x86 compat entry code does (e.g.
+    // Linux's entry_64_compat.S), so 32-bit sentinels such as
+    // `AT_FDCWD`/`-1` still compare correctly against the native i64 ABI.
+    let a1 = frame.ebx as i32 as i64;
+    let a2 = frame.ecx as i32 as i64;
+    let a3 = frame.edx as i32 as i64;
+    let a4 = frame.esi as i32 as i64;
+    let a5 = frame.edi as i32 as i64;
+    let a6 = frame.ebp as i32 as i64;
+
+    dispatch_syscall(syscall_no, a1, a2, a3, a4, a5, a6)
+}
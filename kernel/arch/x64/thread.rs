@@ -1,11 +1,15 @@
 use super::{
     address::VAddr,
-    gdt::{USER_CS64, USER_DS},
+    gdt::{USER_CS32, USER_CS64, USER_DS, USER_DS32},
     syscall::SyscallFrame,
     tss::TSS,
     SpinLockGuard, UserVAddr, KERNEL_STACK_SIZE,
 };
 use super::{cpu_local::cpu_local_head, gdt::USER_RPL};
+use super::fpu::{self, xsave_area_num_pages};
+use super::preempt;
+use super::signal::PendingSignalFrame;
+use crate::arch::ArchThread;
 use crate::mm::page_allocator::{alloc_pages, AllocPageFlags};
 use crate::result::Result;
 use x86::current::segmentation::wrfsbase;
@@ -17,14 +21,47 @@ pub struct Thread {
     pub(super) xsave_area: Option<VAddr>,
     interrupt_stack: VAddr,
     syscall_stack: VAddr,
+    /// Whether this is a 32-bit (x86_32 compat) userland thread. Consulted
+    /// whenever we build a frame that's restored by IRET or read by a
+    /// syscall dispatcher, since compat threads use the 32-bit code/data
+    /// segments and a 32-bit signal frame layout.
+    pub(crate) is_compat: bool,
+    /// This thread's saved `preempt_count` depth, restored into
+    /// `cpu_local_head().preempt_count` whenever it's switched in. Needed
+    /// because a thread doesn't always resume at the `switch()` call site
+    /// that switched it out (e.g. it may instead start at its entry
+    /// trampoline), so the count can't be left as a bare CPU-global
+    /// straddling `do_switch_thread`.
+    pub(super) preempt_count: u32,
+    /// Signal frames staged by `kernel::signal::deliver_pending_signals`
+    /// while this thread's address space wasn't the active one. Must be
+    /// drained (via `commit_pending_signal_frames`) the next time this
+    /// thread's page tables become active, before it reaches userland,
+    /// since writing them any earlier would land in whatever address
+    /// space happened to be active at delivery time instead.
+    pending_signal_frames: alloc::vec::Vec<PendingSignalFrame>,
 }
 
 extern "C" {
+    /// Must call `preempt::schedule_tail()` before running any thread
+    /// code: see that function's doc comment.
     fn kthread_entry();
+    /// Must call `preempt::schedule_tail()`; see `kthread_entry`.
     fn userland_entry();
+    /// Must call `preempt::schedule_tail()`; see `kthread_entry`.
     fn forked_child_entry();
+    /// Must call `preempt::schedule_tail()`; see `kthread_entry`.
     fn signal_handler_entry();
-    fn do_switch_thread(prev_rsp: *const u64, next_rsp: *const u64);
+    /// Same as `signal_handler_entry`, but resumes via `iretq` into
+    /// `USER_CS32`/`USER_DS32` instead of `sysretq`; see
+    /// `set_signal_entry`'s `is_compat` branch.
+    fn compat_signal_handler_entry();
+    /// The `is_current_process` counterpart of `signal_handler_entry`,
+    /// jumped to directly instead of switched into.
+    fn direct_signal_handler_entry();
+    /// The `is_current_process` counterpart of `compat_signal_handler_entry`.
+    fn direct_compat_signal_handler_entry();
+    fn do_switch_thread(prev_rsp: *const u64, next_rsp: *const u64, next_thread: *mut Thread);
 }
 
 unsafe fn push_stack(mut rsp: *mut u64, value: u64) -> *mut u64 {
@@ -33,9 +70,9 @@ unsafe fn push_stack(mut rsp: *mut u64, value: u64) -> *mut u64 {
     rsp
 }
 
-impl Thread {
+impl ArchThread for Thread {
     #[allow(unused)]
-    pub fn new_kthread(ip: VAddr, sp: VAddr) -> Thread {
+    fn new_kthread(ip: VAddr, sp: VAddr) -> Thread {
         let interrupt_stack = alloc_pages(1, AllocPageFlags::KERNEL)
             .expect("failed to allocate kernel stack")
             .as_vaddr();
@@ -68,30 +105,45 @@ impl Thread {
             xsave_area: None,
             interrupt_stack,
             syscall_stack,
+            is_compat: false,
+            preempt_count: 0,
+            pending_signal_frames: alloc::vec::Vec::new(),
         }
     }
 
-    pub fn new_user_thread(ip: UserVAddr, sp: UserVAddr, kernel_sp: VAddr) -> Thread {
+    fn new_user_thread(ip: UserVAddr, sp: UserVAddr, kernel_sp: VAddr, is_compat: bool) -> Thread {
         let interrupt_stack = alloc_pages(1, AllocPageFlags::KERNEL)
             .expect("failed to allocate kernel stack")
             .as_vaddr();
         let syscall_stack = alloc_pages(1, AllocPageFlags::KERNEL)
             .expect("failed to allocate kernel stack")
             .as_vaddr();
-        // TODO: Check the size of XSAVE area.
-        let xsave_area = alloc_pages(1, AllocPageFlags::KERNEL)
+        let xsave_area = alloc_pages(xsave_area_num_pages(), AllocPageFlags::KERNEL)
             .expect("failed to allocate xsave area")
             .as_vaddr();
 
+        let (user_cs, user_ds) = if is_compat {
+            (USER_CS32, USER_DS32)
+        } else {
+            (USER_CS64, USER_DS)
+        };
+        // Compat binaries only ever see a 32-bit address space; make sure
+        // we never hand them a RIP/RSP IRET would misinterpret.
+        let (ip, sp) = if is_compat {
+            (ip.value() as u32 as u64, sp.value() as u32 as u64)
+        } else {
+            (ip.value() as u64, sp.value() as u64)
+        };
+
         let rsp = unsafe {
             let mut rsp: *mut u64 = kernel_sp.as_mut_ptr();
 
             // Registers to be restored by IRET.
-            rsp = push_stack(rsp, (USER_DS | USER_RPL) as u64); // SS
-            rsp = push_stack(rsp, sp.value() as u64); // user RSP
+            rsp = push_stack(rsp, (user_ds | USER_RPL) as u64); // SS
+            rsp = push_stack(rsp, sp); // user RSP
             rsp = push_stack(rsp, 0x202); // RFLAGS (interrupts enabled).
-            rsp = push_stack(rsp, (USER_CS64 | USER_RPL) as u64); // CS
-            rsp = push_stack(rsp, ip.value() as u64); // RIP
+            rsp = push_stack(rsp, (user_cs | USER_RPL) as u64); // CS
+            rsp = push_stack(rsp, ip); // RIP
 
             // Registers to be restored in do_switch_thread().
             rsp = push_stack(rsp, userland_entry as *const u8 as u64); // RIP.
@@ -112,10 +164,13 @@ impl Thread {
             xsave_area: Some(xsave_area),
             interrupt_stack,
             syscall_stack,
+            is_compat,
+            preempt_count: 0,
+            pending_signal_frames: alloc::vec::Vec::new(),
         }
     }
 
-    pub fn new_idle_thread() -> Thread {
+    fn new_idle_thread() -> Thread {
         let interrupt_stack = alloc_pages(1, AllocPageFlags::KERNEL)
             .expect("failed to allocate kernel stack")
             .as_vaddr();
@@ -129,25 +184,33 @@ impl Thread {
             xsave_area: None,
             interrupt_stack,
             syscall_stack,
+            is_compat: false,
+            preempt_count: 0,
+            pending_signal_frames: alloc::vec::Vec::new(),
         }
     }
 
-    pub fn fork(&self, frame: &SyscallFrame) -> Result<Thread> {
-        // TODO: Check the size of XSAVE area.
-        let xsave_area = alloc_pages(1, AllocPageFlags::KERNEL)
+    fn fork(&self, frame: &SyscallFrame) -> Result<Thread> {
+        let xsave_area = alloc_pages(xsave_area_num_pages(), AllocPageFlags::KERNEL)
             .expect("failed to allocate xsave area")
             .as_vaddr();
 
+        let (user_cs, user_ds) = if self.is_compat {
+            (USER_CS32, USER_DS32)
+        } else {
+            (USER_CS64, USER_DS)
+        };
+
         let rsp = unsafe {
             let kernel_sp =
                 alloc_pages(1, AllocPageFlags::KERNEL).expect("failed allocate kernel stack");
             let mut rsp: *mut u64 = kernel_sp.as_mut_ptr();
 
             // Registers to be restored by IRET.
-            rsp = push_stack(rsp, (USER_DS | USER_RPL) as u64); // SS
+            rsp = push_stack(rsp, (user_ds | USER_RPL) as u64); // SS
             rsp = push_stack(rsp, frame.rsp); // user RSP
             rsp = push_stack(rsp, frame.rflags); // user RFLAGS.
-            rsp = push_stack(rsp, (USER_CS64 | USER_RPL) as u64); // CS
+            rsp = push_stack(rsp, (user_cs | USER_RPL) as u64); // CS
             rsp = push_stack(rsp, frame.rip); // user RIP
 
             // Registers to be restored in forked_child_entry,
@@ -186,18 +249,22 @@ impl Thread {
             xsave_area: Some(xsave_area),
             interrupt_stack,
             syscall_stack,
+            is_compat: self.is_compat,
+            preempt_count: 0,
+            pending_signal_frames: alloc::vec::Vec::new(),
         })
     }
 
-    pub(super) unsafe fn set_signal_entry(
+    unsafe fn set_signal_entry(
         mut this: SpinLockGuard<'_, Thread>,
-        user_rip: u64,
-        user_rsp: u64,
+        entry_ip: u64,
+        entry_sp: u64,
         arg1: u64,
         arg2: u64,
         arg3: u64,
         is_current_process: bool,
     ) {
+        let is_compat = this.is_compat;
         let mut tmp = [0u64; 8];
         let mut rsp = if is_current_process {
             tmp.as_mut_ptr().add(tmp.len())
@@ -205,21 +272,52 @@ impl Thread {
             this.rsp as *mut u64
         };
 
-        // Registers to be restored in signal_handler_entry().
-        rsp = push_stack(rsp, user_rsp); // User RSP.
-        rsp = push_stack(rsp, user_rip); // User RIP.
-        rsp = push_stack(rsp, 0x202); // User RFLAGS (interrupts enabled).
-        rsp = push_stack(rsp, arg1); // User RDI.
-        rsp = push_stack(rsp, arg2); // User RSI.
-        rsp = push_stack(rsp, arg3); // User RDX.
+        if is_compat {
+            // `sysretq` is hard-wired to resume in 64-bit long mode (RIP
+            // from RCX, fixed STAR-MSR CS/SS): it has no encoding for
+            // `USER_CS32`/`USER_DS32`, so a compat thread needs an IRET
+            // frame here instead, the same way `new_user_thread`/`fork`
+            // enter userland for it. Any signal frame(s) entry_sp depends
+            // on were already staged onto the user stack by the caller
+            // (see kernel::signal::deliver_pending_signals).
+            rsp = push_stack(rsp, (USER_DS32 | USER_RPL) as u64); // User SS.
+            rsp = push_stack(rsp, entry_sp); // User RSP.
+            rsp = push_stack(rsp, 0x202); // User RFLAGS (interrupts enabled).
+            rsp = push_stack(rsp, (USER_CS32 | USER_RPL) as u64); // User CS.
+            rsp = push_stack(rsp, entry_ip); // User RIP.
+            rsp = push_stack(rsp, arg1); // User RDI.
+            rsp = push_stack(rsp, arg2); // User RSI.
+            rsp = push_stack(rsp, arg3); // User RDX.
+        } else {
+            // Registers to be restored in signal_handler_entry(). Any
+            // signal frame(s) entry_sp depends on were already staged
+            // onto the user stack by the caller (see
+            // kernel::signal::deliver_pending_signals).
+            rsp = push_stack(rsp, entry_sp); // User RSP.
+            rsp = push_stack(rsp, entry_ip); // User RIP.
+            rsp = push_stack(rsp, 0x202); // User RFLAGS (interrupts enabled).
+            rsp = push_stack(rsp, arg1); // User RDI.
+            rsp = push_stack(rsp, arg2); // User RSI.
+            rsp = push_stack(rsp, arg3); // User RDX.
+        }
 
         if is_current_process {
             // Resume the user process directly from the signal handler.
+            let target = if is_compat {
+                direct_compat_signal_handler_entry as *const u8 as u64
+            } else {
+                direct_signal_handler_entry as *const u8 as u64
+            };
             drop(this);
-            asm!("mov rsp, rax; jmp direct_signal_handler_entry", in("rax") rsp);
+            asm!("mov rsp, {rsp}", "jmp {target}", rsp = in(reg) rsp, target = in(reg) target);
         } else {
             // Registers to be restored in do_switch_thread().
-            rsp = push_stack(rsp, signal_handler_entry as *const u8 as u64); // RIP.
+            let entry = if is_compat {
+                compat_signal_handler_entry as *const u8 as u64
+            } else {
+                signal_handler_entry as *const u8 as u64
+            };
+            rsp = push_stack(rsp, entry); // RIP.
             rsp = push_stack(rsp, 0); // Initial RBP.
             rsp = push_stack(rsp, 0); // Initial RBX.
             rsp = push_stack(rsp, 0); // Initial R12.
@@ -231,34 +329,116 @@ impl Thread {
             this.rsp = rsp as u64;
         }
     }
-}
 
-pub fn switch_thread(prev: &mut Thread, next: &mut Thread) {
-    let head = cpu_local_head();
+    // Safe to enter from interrupt context: `prev`'s full state (every
+    // callee-saved register plus RFLAGS) is saved onto its own kernel
+    // stack by `do_switch_thread` before the CPU ever starts running
+    // `next`, rather than assuming `prev` is at a voluntary yield point.
+    fn switch(prev: &mut Thread, next: &mut Thread) {
+        let head = cpu_local_head();
+
+        // `preempt_count` lives in the per-CPU head but belongs to the
+        // running thread: `prev` may not
+        // resume at the `preempt_enable()` call below (it might instead
+        // start fresh at its entry trampoline), so save its depth onto
+        // `prev` itself and load `next`'s own saved depth in, the same
+        // way `fsbase` is carried across the switch.
+        prev.preempt_count = head.preempt_count;
+        head.preempt_count = next.preempt_count;
+
+        // Switching stacks out from under a thread must itself not be
+        // preempted. If `next` has never run before, it resumes at its
+        // entry trampoline rather than at the `preempt_enable()` below;
+        // the trampoline balances this disable itself by calling
+        // `preempt::schedule_tail()` first.
+        preempt::preempt_disable();
+
+        // Switch the kernel stack.
+        head.rsp0 = (next.syscall_stack.value() + KERNEL_STACK_SIZE) as u64;
+        TSS.as_mut()
+            .set_rsp0((next.interrupt_stack.value() + KERNEL_STACK_SIZE) as u64);
+
+        // Don't save/restore the XSAVE area eagerly: mark it absent from the
+        // registers and let the first FP/SIMD instruction `next` executes
+        // trap into #NM, where `fpu::handle_device_not_available` lazily
+        // saves whichever thread currently owns the FPU and restores
+        // `next`'s state. Threads that never touch SIMD never pay for it.
+        // (Falls back to an eager save/restore right here on CPUs that
+        // can't be trusted with the lazy path; see `switch_fpu_state`.)
+        unsafe {
+            fpu::switch_fpu_state(prev, next);
+        }
 
-    // Switch the kernel stack.
-    head.rsp0 = (next.syscall_stack.value() + KERNEL_STACK_SIZE) as u64;
-    TSS.as_mut()
-        .set_rsp0((next.interrupt_stack.value() + KERNEL_STACK_SIZE) as u64);
+        // Fill an invalid value for now: must be initialized in interrupt handlers.
+        head.rsp3 = 0xbaad_5a5a_5b5b_baad;
+
+        // Note: `next`'s signal frames staged while it wasn't running (see
+        // `pending_signal_frames`) are deliberately *not* drained here.
+        // Nothing in `switch()` activates `next`'s address space (that's
+        // the process/vmspace layer's job, outside `ArchThread`), so there
+        // is no point at which writing through them would be provably
+        // safe. Instead `next` gets its own `Thread` pointer in rdx across the switch and
+        // drains them itself, from its own entry trampoline
+        // (`commit_pending_signal_frames` below, called from entry.S)
+        // right before it reaches userland, which is the first point we
+        // can actually prove its address space is the active one.
+        unsafe {
+            wrfsbase(next.fsbase);
+            do_switch_thread(
+                &mut prev.rsp as *mut u64,
+                &mut next.rsp as *mut u64,
+                next as *mut Thread,
+            );
+        }
 
-    // Save and restore the XSAVE area (i.e. XMM/YMM registrers).
-    unsafe {
-        use core::arch::x86_64::{_xrstor64, _xsave64};
+        // Control returns here once a later switch (on any CPU) switches
+        // back into `prev`, balancing the `preempt_disable` above.
+        preempt::preempt_enable();
+    }
+}
 
-        let xsave_mask = x86::controlregs::xcr0().bits();
-        if let Some(xsave_area) = prev.xsave_area.as_ref() {
-            _xsave64(xsave_area.as_mut_ptr(), xsave_mask);
-        }
-        if let Some(xsave_area) = next.xsave_area.as_ref() {
-            _xrstor64(xsave_area.as_mut_ptr(), xsave_mask);
+impl Thread {
+    /// Stages a signal frame to be written to this thread's user stack
+    /// once its address space is next active, because it isn't right
+    /// now (see `PendingSignalFrame`'s doc comment).
+    pub fn stage_pending_signal_frame(&mut self, frame: PendingSignalFrame) {
+        self.pending_signal_frames.push(frame);
+    }
+
+    /// Writes out every signal frame staged by `stage_pending_signal_frame`
+    /// and clears the queue. Must only be called once this thread's
+    /// address space is the one active on the current CPU, and before it
+    /// reaches userland.
+    unsafe fn commit_pending_signal_frames(&mut self) {
+        for frame in self.pending_signal_frames.drain(..) {
+            frame.commit();
         }
     }
+}
 
-    // Fill an invalid value for now: must be initialized in interrupt handlers.
-    head.rsp3 = 0xbaad_5a5a_5b5b_baad;
+/// Called by the entry trampolines (`entry.S`) that can reach userland,
+/// for the `Thread` `do_switch_thread` just switched into (handed to them
+/// in rdx — see `entry.S`'s file header). This is the first point after a
+/// switch that's provably safe to write into `thread`'s user stack: by
+/// now `do_switch_thread` has returned and, for every caller of this
+/// function, the next instructions run as `thread` itself rather than as
+/// whatever `prev` was, so if anything ever activates a distinct address
+/// space for `thread` it must have happened no later than here.
+#[no_mangle]
+unsafe extern "C" fn commit_pending_signal_frames(thread: *mut Thread) {
+    (*thread).commit_pending_signal_frames();
+}
 
-    unsafe {
-        wrfsbase(next.fsbase);
-        do_switch_thread(&mut prev.rsp as *mut u64, &mut next.rsp as *mut u64);
+impl Drop for Thread {
+    /// A CPU's `fpu_owner` is a raw pointer into whichever `Thread` last
+    /// left its state in the FPU registers. If this thread is torn down
+    /// while still owning the FPU of the CPU running this drop, clear that
+    /// CPU's pointer so a later `#NM` there doesn't `_xsave64` into the
+    /// `xsave_area` we're about to free. Other CPUs' `fpu_owner` pointers
+    /// are not checked, and migration does not go through here.
+    fn drop(&mut self) {
+        unsafe {
+            fpu::forget_fpu_owner(self as *mut Thread);
+        }
     }
 }
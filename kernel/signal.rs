@@ -0,0 +1,119 @@
+//! Signal delivery.
+
+use crate::arch::x64::signal::{PendingSignalFrame, Resume};
+use crate::arch::x64::syscall::SyscallFrame;
+use crate::arch::x64::syscall32::CompatSyscallFrame;
+use crate::arch::{ArchThread, Thread};
+use crate::process::Process;
+use crate::sync::SpinLock;
+
+/// The context the kernel was entered from, for whichever of a native
+/// 64-bit `syscall` entry or a 32-bit compat `int 0x80` entry actually
+/// interrupted the process — each has its own frame layout (see
+/// `arch::x64::syscall32`'s module doc) and so its own way of becoming a
+/// `Resume`.
+pub enum InterruptedFrame<'a> {
+    Native(&'a SyscallFrame),
+    Compat(&'a CompatSyscallFrame),
+}
+
+impl<'a> InterruptedFrame<'a> {
+    fn resume(&self) -> Resume {
+        match self {
+            InterruptedFrame::Native(frame) => Resume::from_interrupted(frame),
+            InterruptedFrame::Compat(frame) => Resume::from_compat_interrupted(frame),
+        }
+    }
+
+    fn stack_top(&self) -> u64 {
+        match self {
+            InterruptedFrame::Native(frame) => frame.rsp,
+            InterruptedFrame::Compat(frame) => frame.rsp,
+        }
+    }
+}
+
+/// Runs over `process`'s pending-and-unblocked signals, delivering each
+/// in turn: builds one handler frame per signal, nested so that each
+/// runs to completion (and `sigreturn`s) before the next one starts, in
+/// order, and finally `sigreturn` from the last one resumes the
+/// genuinely-interrupted context. `frame` is the syscall entry the kernel
+/// was entered from (a native `syscall` or a compat `int 0x80`; never an
+/// asynchronous IRQ/timer frame, see `Resume`'s doc comment) — pass `InterruptedFrame::Compat`
+/// whenever `thread`'s `is_compat` is set, since a compat thread is only
+/// ever entered through `int 0x80`, never the native `syscall` path.
+pub fn deliver_pending_signals(
+    process: &Process,
+    thread: &SpinLock<Thread>,
+    frame: InterruptedFrame<'_>,
+    is_current_process: bool,
+) {
+    let original_sigmask = *process.blocked_signals.lock();
+
+    // Snapshot every signal to deliver this round up front: which ones
+    // qualify must not change while we're building their frames below.
+    let mut to_deliver = Vec::new();
+    {
+        let mut pending = process.pending_signals.lock();
+        let mut deliverable = *pending & !original_sigmask;
+        while deliverable != 0 {
+            let signum = deliverable.trailing_zeros();
+            deliverable &= !(1 << signum);
+            *pending &= !(1 << signum);
+            let action = process.signal_actions.lock()[signum as usize];
+            to_deliver.push((signum, action));
+        }
+    }
+
+    if to_deliver.is_empty() {
+        return;
+    }
+
+    let is_compat = thread.lock().is_compat;
+
+    // Build the frames from the last-delivered signal back to the
+    // first: signal N's frame restores the genuinely-interrupted
+    // context on sigreturn; signal K's frame (K < N) instead resumes
+    // into signal K+1's handler entry. The last one built this way
+    // (signal 1's) is what we actually activate below, so handlers run
+    // in delivery order and nothing gets clobbered or dropped.
+    let mut resume = frame.resume();
+    let mut resume_sigmask = original_sigmask;
+    let mut stack_top = frame.stack_top();
+
+    for &(signum, action) in to_deliver.iter().rev() {
+        // `push_signal_frame` writes through a raw pointer into the
+        // target's user stack, which is only safe when the target's page
+        // tables are the ones active right now. When delivering to a
+        // process other than the one currently running, stage the write
+        // instead: its address is pure arithmetic (doesn't touch memory),
+        // so the rest of the chain can still be built, but the actual
+        // write waits until the target thread's own return-to-userland
+        // path drains it (see `Thread::commit_pending_signal_frames`).
+        let pending = PendingSignalFrame::new(resume, resume_sigmask, is_compat, stack_top);
+        let new_rsp = pending.new_rsp();
+        if is_current_process {
+            unsafe { pending.commit() };
+        } else {
+            thread.lock().stage_pending_signal_frame(pending);
+        }
+
+        resume = Resume::into_handler(action.handler as u64, new_rsp, signum as u64, 0, 0);
+        resume_sigmask = original_sigmask | action.sa_mask | (1 << signum);
+        stack_top = new_rsp;
+    }
+
+    *process.blocked_signals.lock() = resume_sigmask;
+
+    unsafe {
+        Thread::set_signal_entry(
+            thread.lock(),
+            resume.rip,
+            resume.rsp,
+            resume.rdi,
+            resume.rsi,
+            resume.rdx,
+            is_current_process,
+        );
+    }
+}